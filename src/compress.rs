@@ -0,0 +1,160 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use actix_web::http::header::{HeaderMap, ACCEPT_ENCODING};
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &["json", "js", "css"];
+const TILE_DIRS: &[&str] = &["hires", "lowres"];
+
+pub(crate) fn is_compressible(path: &Path) -> bool {
+    if path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSIBLE_EXTENSIONS.contains(&ext))
+    {
+        return true;
+    }
+
+    path.iter()
+        .any(|comp| TILE_DIRS.contains(&comp.to_string_lossy().as_ref()))
+}
+
+pub async fn precompress_tree(root: &Path) -> io::Result<()> {
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            if entry.file_type().await?.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            if is_compressible(&path) {
+                precompress_file(&path).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn precompress_file(path: &Path) -> io::Result<()> {
+    let gz_path = with_appended_extension(path, "gz");
+    let br_path = with_appended_extension(path, "br");
+
+    let source = fs::read(path).await?;
+
+    if !fs::try_exists(&gz_path).await? {
+        let mut encoder = GzipEncoder::new(fs::File::create(&gz_path).await?);
+        encoder.write_all(&source).await?;
+        encoder.shutdown().await?;
+    }
+
+    if !fs::try_exists(&br_path).await? {
+        let mut encoder = BrotliEncoder::new(fs::File::create(&br_path).await?);
+        encoder.write_all(&source).await?;
+        encoder.shutdown().await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn with_appended_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(".");
+    name.push(ext);
+    path.with_file_name(name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreferredEncoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl PreferredEncoding {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let accepted = headers
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+
+        let accepts = |encoding: &str| {
+            accepted.split(',').any(|token| {
+                let mut parts = token.split(';').map(str::trim);
+
+                if !parts.next().is_some_and(|name| name.eq_ignore_ascii_case(encoding)) {
+                    return false;
+                }
+
+                let q = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                q > 0.0
+            })
+        };
+
+        if accepts("br") {
+            Self::Brotli
+        } else if accepts("gzip") {
+            Self::Gzip
+        } else {
+            Self::Identity
+        }
+    }
+
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::Brotli => Some("br"),
+            Self::Gzip => Some("gz"),
+            Self::Identity => None,
+        }
+    }
+
+    pub fn content_encoding(self) -> Option<actix_web::http::header::ContentEncoding> {
+        match self {
+            Self::Brotli => Some(actix_web::http::header::ContentEncoding::Brotli),
+            Self::Gzip => Some(actix_web::http::header::ContentEncoding::Gzip),
+            Self::Identity => None,
+        }
+    }
+
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Self::Brotli => Some("br"),
+            Self::Gzip => Some("gzip"),
+            Self::Identity => None,
+        }
+    }
+}
+
+pub async fn compress_on_the_fly(
+    source: &[u8],
+    encoding: PreferredEncoding,
+) -> io::Result<Vec<u8>> {
+    match encoding {
+        PreferredEncoding::Brotli => {
+            let mut encoder = BrotliEncoder::new(Vec::new());
+            encoder.write_all(source).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        }
+        PreferredEncoding::Gzip => {
+            let mut encoder = GzipEncoder::new(Vec::new());
+            encoder.write_all(source).await?;
+            encoder.shutdown().await?;
+            Ok(encoder.into_inner())
+        }
+        PreferredEncoding::Identity => Ok(source.to_vec()),
+    }
+}
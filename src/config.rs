@@ -1,15 +1,18 @@
-use std::{fs, io::Write, path::PathBuf, sync::OnceLock};
+use std::{fmt::Display, fs, io::Write, path::PathBuf, sync::OnceLock};
 
 use default_from_serde::SerdeDefault;
 use dirs::config_dir;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_inline_default::serde_inline_default;
+use serde_json::Value;
 
 static MASTER_CONFIG: OnceLock<MasterConfig> = OnceLock::new();
 
 #[serde_inline_default]
 #[derive(Serialize, Deserialize, SerdeDefault)]
 pub struct MasterConfig {
+    #[serde_inline_default(1)]
+    pub version: u32,
     #[serde_inline_default(PathBuf::from("config"))]
     pub bluemap_config: PathBuf,
     #[serde_inline_default(PathBuf::from("web"))]
@@ -18,6 +21,8 @@ pub struct MasterConfig {
     pub bluemap_jar: PathBuf,
     #[serde_inline_default(PathBuf::from("artifacts"))]
     pub artifacts: PathBuf,
+    #[serde_inline_default(std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4))]
+    pub max_concurrent_renders: usize,
 }
 
 impl Config for MasterConfig {
@@ -28,8 +33,33 @@ impl Config for MasterConfig {
     fn oncelock() -> &'static OnceLock<Self> {
         &MASTER_CONFIG
     }
+
+    // No shape changes since `version` was introduced yet; add a closure
+    // here (indexed by the on-disk version it migrates *from*) whenever a
+    // future field rename/type change needs one.
+    fn migrations() -> &'static [fn(Value) -> Value] {
+        &[]
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigLoadError {
+    pub ident: &'static str,
+    pub reason: String,
 }
 
+impl Display for ConfigLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to load {} config, original backed up to {}.json.bak: {}",
+            self.ident, self.ident, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ConfigLoadError {}
+
 pub trait Config: Serialize + DeserializeOwned + Default
 where
     Self: 'static,
@@ -37,6 +67,10 @@ where
     fn ident() -> &'static str;
     fn oncelock() -> &'static OnceLock<Self>;
 
+    fn migrations() -> &'static [fn(Value) -> Value] {
+        &[]
+    }
+
     fn path() -> PathBuf {
         config_dir()
             .unwrap()
@@ -63,25 +97,51 @@ where
         file.write_all(&content).unwrap();
     }
 
-    fn load() {
+    fn load() -> Result<(), ConfigLoadError> {
         let path = Self::path();
 
         if !path.exists() {
             let def = Self::default();
             def.save();
             let _ = Self::oncelock().set(def);
-            return;
+            return Ok(());
         }
 
-        let content = fs::read_to_string(path).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
 
-        let _ = Self::oncelock().set(if let Ok(val) = serde_json::from_str::<Self>(&content) {
-            val
-        } else {
-            let def = Self::default();
-            def.save();
-            def
-        });
+        let to_load_error = |reason: String| {
+            let backup = Self::path().with_extension("json.bak");
+            let _ = fs::write(backup, &content);
+            ConfigLoadError {
+                ident: Self::ident(),
+                reason,
+            }
+        };
+
+        let mut value: Value =
+            serde_json::from_str(&content).map_err(|e| to_load_error(e.to_string()))?;
+
+        let on_disk_version = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .unwrap_or(0) as usize;
+
+        let mut migrated = false;
+
+        for migration in Self::migrations().iter().skip(on_disk_version) {
+            value = migration(value);
+            migrated = true;
+        }
+
+        let parsed: Self =
+            serde_json::from_value(value).map_err(|e| to_load_error(e.to_string()))?;
+
+        if migrated {
+            parsed.save();
+        }
+
+        let _ = Self::oncelock().set(parsed);
+        Ok(())
     }
 
     fn get() -> &'static Self {
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use actix_web::{http::StatusCode, web, HttpResponseBuilder};
+use serde::Serialize;
+use tokio::fs;
+use tokio::process::Child;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::map::Dimension;
+
+pub type JobId = String;
+
+pub(crate) type RenderKey = (PathBuf, PathBuf, PathBuf, Dimension);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running { percent: u8 },
+    Completed,
+    Failed { error: String },
+    Cancelled,
+}
+
+pub(crate) struct JobHandle {
+    pub(crate) key: RenderKey,
+    pub(crate) child: Mutex<Option<Child>>,
+    pub(crate) cancelled: Arc<AtomicBool>,
+}
+
+struct JobEntry {
+    state: JobState,
+    handle: Arc<JobHandle>,
+}
+
+pub(crate) enum FindOrCreate {
+    Found(JobId),
+    DestinationExists,
+    Created(JobId, Arc<JobHandle>),
+}
+
+static JOBS: OnceLock<RwLock<HashMap<JobId, JobEntry>>> = OnceLock::new();
+
+pub struct JobRegistry;
+
+impl JobRegistry {
+    fn jobs() -> &'static RwLock<HashMap<JobId, JobEntry>> {
+        JOBS.get_or_init(|| RwLock::new(HashMap::new()))
+    }
+
+    // Finds a running job for `key`, or creates one, under a single write
+    // lock held across the `dest`-exists check so two identical requests
+    // arriving concurrently can't both slip past and start duplicate
+    // renders.
+    pub(crate) async fn find_or_create(
+        key: RenderKey,
+        dest: &std::path::Path,
+    ) -> std::io::Result<FindOrCreate> {
+        let mut jobs = Self::jobs().write().await;
+
+        if let Some(id) = jobs
+            .iter()
+            .find(|(_, entry)| {
+                entry.handle.key == key
+                    && matches!(entry.state, JobState::Queued | JobState::Running { .. })
+            })
+            .map(|(id, _)| id.clone())
+        {
+            return Ok(FindOrCreate::Found(id));
+        }
+
+        if fs::try_exists(dest).await? {
+            return Ok(FindOrCreate::DestinationExists);
+        }
+
+        let id = fastrand::u64(..).to_string();
+        let handle = Arc::new(JobHandle {
+            key,
+            child: Mutex::new(None),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        });
+
+        jobs.insert(
+            id.clone(),
+            JobEntry {
+                state: JobState::Queued,
+                handle: handle.clone(),
+            },
+        );
+
+        Ok(FindOrCreate::Created(id, handle))
+    }
+
+    pub(crate) async fn set_state(id: &str, state: JobState) {
+        if let Some(entry) = Self::jobs().write().await.get_mut(id) {
+            entry.state = state;
+        }
+    }
+
+    pub async fn state(id: &str) -> Option<JobState> {
+        Self::jobs().read().await.get(id).map(|e| e.state.clone())
+    }
+
+    pub async fn cancel(id: &str) -> bool {
+        let handle = match Self::jobs().read().await.get(id) {
+            Some(entry) => entry.handle.clone(),
+            None => return false,
+        };
+
+        handle.cancelled.store(true, Ordering::SeqCst);
+
+        if let Some(child) = handle.child.lock().await.as_mut() {
+            let _ = child.kill().await;
+        }
+
+        Self::set_state(id, JobState::Cancelled).await;
+        true
+    }
+}
+
+pub async fn get_job(path: web::Path<String>) -> actix_web::HttpResponse {
+    match JobRegistry::state(&path).await {
+        Some(state) => HttpResponseBuilder::new(StatusCode::OK).json(state),
+        None => HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+    }
+}
+
+pub async fn cancel_job(path: web::Path<String>) -> actix_web::HttpResponse {
+    if JobRegistry::cancel(&path).await {
+        HttpResponseBuilder::new(StatusCode::OK).finish()
+    } else {
+        HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+    }
+}
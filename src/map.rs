@@ -1,8 +1,8 @@
-use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
+use std::process::Stdio;
+use std::sync::{Arc, OnceLock};
 
 use actix_files::NamedFile;
 use actix_web::HttpRequest;
@@ -11,17 +11,20 @@ use default_from_serde::SerdeDefault;
 use serde::{Deserialize, Serialize};
 use serde_inline_default::serde_inline_default;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
-use tokio::sync::broadcast::{channel, Sender};
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 
+use crate::compress;
+use crate::job::{JobHandle, JobId, JobRegistry, JobState};
 use crate::{Config, MasterConfig};
 
-// source - destination - template - dimension
-#[allow(clippy::type_complexity)]
-static mut LOCKS: OnceLock<
-    HashMap<(PathBuf, PathBuf, PathBuf, Dimension), Sender<Result<(), MapError>>>,
-> = OnceLock::new();
+static RENDER_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+fn render_semaphore() -> &'static Semaphore {
+    RENDER_SEMAPHORE.get_or_init(|| Semaphore::new(MasterConfig::get().max_concurrent_renders))
+}
 
 #[allow(non_snake_case)]
 #[serde_inline_default]
@@ -63,22 +66,31 @@ pub struct SettingsGlobal {
 
 #[derive(Debug, Clone)]
 pub enum MapError {
-    UnzipFailed,
+    UnzipFailed(String),
     ConfigTemplateNotFound,
-    RenderingFiled,
+    RenderingFailed {
+        stderr: String,
+        exit_code: Option<i32>,
+    },
     DestinationExist,
     External { reason: String },
+    Cancelled,
 }
 
 impl Display for MapError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Self::UnzipFailed => "unzip failed",
-            Self::ConfigTemplateNotFound => "config template not found",
-            Self::RenderingFiled => "rendering failed",
-            Self::DestinationExist => "destination exist",
-            Self::External { reason } => reason,
-        })
+        match self {
+            Self::UnzipFailed(reason) => write!(f, "unzip failed: {reason}"),
+            Self::ConfigTemplateNotFound => f.write_str("config template not found"),
+            Self::RenderingFailed { stderr, exit_code } => write!(
+                f,
+                "rendering failed (exit code {}): {stderr}",
+                exit_code.map_or_else(|| "unknown".to_string(), |c| c.to_string())
+            ),
+            Self::DestinationExist => f.write_str("destination exist"),
+            Self::External { reason } => f.write_str(reason),
+            Self::Cancelled => f.write_str("render cancelled"),
+        }
     }
 }
 
@@ -115,8 +127,7 @@ impl Map {
         dest: &Path,
         template: &Path,
         dimension: Dimension,
-    ) -> Result<(), MapError> {
-        let locks = Self::locks();
+    ) -> Result<JobId, MapError> {
         let key = (
             source.to_path_buf(),
             dest.to_path_buf(),
@@ -124,124 +135,144 @@ impl Map {
             dimension,
         );
 
-        if let Some(tx) = locks.get(&key) {
-            return match tx.subscribe().recv().await {
-                Ok(res) => res,
-                Err(e) => Err(MapError::External {
-                    reason: e.to_string(),
-                }),
-            };
-        }
-
-        let channel = channel(1).0;
-        locks.insert(key.clone(), channel.clone());
-
-        let res = match Self::render_internal(source, dest, template, dimension).await {
-            Ok(res) => Ok(res),
-            Err(e) => {
-                if let Some(e) = e.downcast_ref::<MapError>() {
-                    Err(e.clone())
-                } else {
-                    Err(MapError::External {
-                        reason: e.to_string(),
-                    })
-                }
-            }
+        let (id, handle) = match JobRegistry::find_or_create(key, dest)
+            .await
+            .map_err(|e| MapError::External {
+                reason: e.to_string(),
+            })? {
+            crate::job::FindOrCreate::Found(id) => return Ok(id),
+            crate::job::FindOrCreate::DestinationExists => return Err(MapError::DestinationExist),
+            crate::job::FindOrCreate::Created(id, handle) => (id, handle),
         };
+        let job_id = id.clone();
+
+        tokio::spawn(async move {
+            let (source, dest, template, dimension) =
+                (&handle.key.0, &handle.key.1, &handle.key.2, handle.key.3);
+
+            let res = Self::render_internal(source, dest, template, dimension, &id, &handle).await;
+
+            // Don't let a job that finished (successfully or not) after being
+            // cancelled clobber the `Cancelled` state `JobRegistry::cancel`
+            // already wrote.
+            if !handle.cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+                JobRegistry::set_state(
+                    &id,
+                    match res {
+                        Ok(()) => JobState::Completed,
+                        Err(e) => {
+                            tracing::error!(error = %e, "render failed");
+                            JobState::Failed {
+                                error: e.to_string(),
+                            }
+                        }
+                    },
+                )
+                .await;
+            }
+        });
 
-        let _ = channel.send(res.clone());
-        let _ = locks.remove(&key);
-
-        res
-    }
-
-    #[allow(clippy::type_complexity)]
-    fn locks(
-    ) -> &'static mut HashMap<(PathBuf, PathBuf, PathBuf, Dimension), Sender<Result<(), MapError>>>
-    {
-        if let Some(locks) = unsafe { LOCKS.get_mut() } {
-            locks
-        } else {
-            let _ = unsafe { LOCKS.set(HashMap::new()) };
-            Self::locks()
-        }
+        Ok(job_id)
     }
 
+    #[tracing::instrument(skip(source, dest, template, handle), fields(job = %id))]
     async fn render_internal(
         source: &Path,
         dest: &Path,
         template: &Path,
         dimension: Dimension,
+        id: &str,
+        handle: &Arc<JobHandle>,
     ) -> Result<(), Box<dyn Error>> {
-        if fs::try_exists(dest).await? {
-            return Err(MapError::DestinationExist.into());
-        }
-
         let master = MasterConfig::get();
-        let id = fastrand::u64(..).to_string();
+        let id = id.to_string();
 
-        let temp_zip = master.maps.join(&id).with_extension("zip");
+        // Held for the rest of the function so at most `max_concurrent_renders`
+        // unzip+java sections run at once; dropped on every return path,
+        // including early `?` errors, releasing the permit automatically.
+        let _permit = render_semaphore().acquire().await?;
 
-        if !fs::try_exists(temp_zip.parent().unwrap()).await? {
-            fs::create_dir_all(temp_zip.parent().unwrap()).await?;
+        if handle.cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(MapError::Cancelled.into());
         }
 
+        JobRegistry::set_state(&id, JobState::Running { percent: 0 }).await;
+
+        let temp_zip = master.maps.join(&id).with_extension("zip");
         let temp_zip_dir = temp_zip.with_extension("");
 
-        fs::copy(source, &temp_zip).await?;
-        let unzip = Command::new("unzip")
-            .args([
-                temp_zip.to_str().unwrap(),
-                "-d",
-                temp_zip_dir.to_str().unwrap(),
-            ])
-            .output()
-            .await?;
-
-        let _ = fs::remove_file(&temp_zip).await;
-
-        if !unzip.status.success() {
-            let _ = fs::remove_dir_all(temp_zip_dir).await;
-            return Err(MapError::UnzipFailed.into());
+        async {
+            if !fs::try_exists(temp_zip.parent().unwrap()).await? {
+                fs::create_dir_all(temp_zip.parent().unwrap()).await?;
+            }
+
+            fs::copy(source, &temp_zip).await?;
+            Ok::<(), Box<dyn Error>>(())
         }
+        .instrument(tracing::info_span!("copy"))
+        .await?;
+
+        async {
+            let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+
+            let extract = crate::zip::extract(
+                temp_zip.clone(),
+                temp_zip_dir.clone(),
+                Arc::clone(&handle.cancelled),
+                progress_tx,
+            );
+
+            let read_progress = async {
+                while let Some(percent) = progress_rx.recv().await {
+                    JobRegistry::set_state(&id, JobState::Running { percent }).await;
+                }
+            };
+
+            let (extracted, ()) = tokio::join!(extract, read_progress);
 
-        let mut dir = fs::read_dir(&temp_zip_dir).await?;
-        let mut items = Vec::new();
+            let _ = fs::remove_file(&temp_zip).await;
 
-        while let Some(direntry) = dir.next_entry().await? {
-            items.push(direntry.path());
-            if items.len() == 2 {
-                break;
+            if let Err(e) = extracted {
+                let _ = fs::remove_dir_all(&temp_zip_dir).await;
+                return Err(MapError::UnzipFailed(e.to_string()).into());
             }
-        }
 
-        if let &[item] = &items.as_slice() {
-            fs::rename(
-                item,
-                temp_zip_dir.with_file_name(format!(
-                    "{}_temp",
-                    item.file_name().unwrap().to_string_lossy()
-                )),
-            )
-            .await?;
-            fs::remove_dir(&temp_zip_dir).await?;
-            fs::rename(
-                temp_zip_dir.with_file_name(format!(
-                    "{}_temp",
-                    item.file_name().unwrap().to_string_lossy()
-                )),
-                temp_zip_dir,
-            )
-            .await?;
-        }
+            tracing::info!("archive extracted");
+
+            let mut dir = fs::read_dir(&temp_zip_dir).await?;
+            let mut items = Vec::new();
+
+            while let Some(direntry) = dir.next_entry().await? {
+                items.push(direntry.path());
+                if items.len() == 2 {
+                    break;
+                }
+            }
 
-        let config = match fs::read_to_string(template).await {
-            Ok(file) => file,
-            Err(_e) => return Err(MapError::ConfigTemplateNotFound.into()),
+            if let &[item] = &items.as_slice() {
+                fs::rename(
+                    item,
+                    temp_zip_dir.with_file_name(format!(
+                        "{}_temp",
+                        item.file_name().unwrap().to_string_lossy()
+                    )),
+                )
+                .await?;
+                fs::remove_dir(&temp_zip_dir).await?;
+                fs::rename(
+                    temp_zip_dir.with_file_name(format!(
+                        "{}_temp",
+                        item.file_name().unwrap().to_string_lossy()
+                    )),
+                    &temp_zip_dir,
+                )
+                .await?;
+            }
+
+            Ok::<(), Box<dyn Error>>(())
         }
-        .replacen("%world%", temp_zip.with_extension("").to_str().unwrap(), 1)
-        .replacen("%dimension%", dimension.to_string().as_str(), 1)
-        .replacen("%name%", dest.file_name().unwrap().to_str().unwrap(), 1);
+        .instrument(tracing::info_span!("unzip"))
+        .await?;
 
         let conf = master
             .bluemap_config
@@ -249,47 +280,148 @@ impl Map {
             .join(&id)
             .with_extension("conf");
 
-        if !fs::try_exists(conf.parent().unwrap()).await? {
-            fs::create_dir_all(conf.parent().unwrap()).await?;
-        }
+        async {
+            let config = match fs::read_to_string(template).await {
+                Ok(file) => file,
+                Err(_e) => return Err(MapError::ConfigTemplateNotFound.into()),
+            }
+            .replacen("%world%", temp_zip.with_extension("").to_str().unwrap(), 1)
+            .replacen("%dimension%", dimension.to_string().as_str(), 1)
+            .replacen("%name%", dest.file_name().unwrap().to_str().unwrap(), 1);
+
+            if !fs::try_exists(conf.parent().unwrap()).await? {
+                fs::create_dir_all(conf.parent().unwrap()).await?;
+            }
+
+            let mut conf_file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&conf)
+                .await?;
+
+            conf_file.write_all(config.as_bytes()).await?;
 
-        let mut conf_file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&conf)
-            .await?;
+            Ok::<(), Box<dyn Error>>(())
+        }
+        .instrument(tracing::info_span!("conf_write"))
+        .await?;
 
-        conf_file.write_all(config.as_bytes()).await?;
+        let rendered = master.bluemap_web.join("maps").join(&id);
 
-        let bluemap = Command::new("java")
-            .args([
-                "-jar",
+        let java_result = async {
+            let command_line = format!(
+                "java -jar {} -c {} -m {} -r",
                 master.bluemap_jar.to_str().unwrap(),
-                "-c",
                 master.bluemap_config.to_str().unwrap(),
-                "-m",
-                id.as_str(),
-                "-r",
-            ])
-            .output()
-            .await?;
+                id
+            );
+            tracing::info!(%command_line, "spawning bluemap");
+
+            if handle.cancelled.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(MapError::Cancelled.into());
+            }
+
+            let mut child = Command::new("java")
+                .args([
+                    "-jar",
+                    master.bluemap_jar.to_str().unwrap(),
+                    "-c",
+                    master.bluemap_config.to_str().unwrap(),
+                    "-m",
+                    id.as_str(),
+                    "-r",
+                ])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+
+            let stdout = child.stdout.take().expect("child spawned with piped stdout");
+            let stderr = child.stderr.take().expect("child spawned with piped stderr");
+            *handle.child.lock().await = Some(child);
+
+            let read_progress = async {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Some(line) = lines.next_line().await? {
+                    if let Some(percent) = Self::parse_progress(&line) {
+                        JobRegistry::set_state(&id, JobState::Running { percent }).await;
+                    }
+                }
+                Ok::<(), std::io::Error>(())
+            };
 
-        let rendered = master.bluemap_web.join("maps").join(id);
+            let read_stderr = async {
+                let mut stderr_buf = String::new();
+                let mut lines = BufReader::new(stderr).lines();
+                while let Some(line) = lines.next_line().await? {
+                    stderr_buf.push_str(&line);
+                    stderr_buf.push('\n');
+                }
+                Ok::<String, std::io::Error>(stderr_buf)
+            };
+
+            let (progress, stderr) = tokio::join!(read_progress, read_stderr);
+            progress?;
+            let stderr = stderr?;
+
+            let status = handle
+                .child
+                .lock()
+                .await
+                .as_mut()
+                .expect("child stored above")
+                .wait()
+                .await?;
+
+            if status.success() {
+                tracing::info!(status = %status, "bluemap exited");
+            } else {
+                tracing::warn!(status = %status, "bluemap exited with a non-zero status");
+            }
+
+            Ok::<_, Box<dyn Error>>((status, stderr))
+        }
+        .instrument(tracing::info_span!("java"))
+        .await;
 
         let _ = fs::remove_dir_all(temp_zip.with_extension("").to_str().unwrap()).await;
-        let _ = fs::remove_file(conf).await;
+        let _ = fs::remove_file(&conf).await;
 
-        if !bluemap.status.success() {
-            let _ = fs::remove_dir_all(rendered).await;
-            return Err(MapError::RenderingFiled.into());
+        let (status, stderr) = java_result?;
+
+        if !status.success() {
+            let _ = fs::remove_dir_all(&rendered).await;
+            return Err(MapError::RenderingFailed {
+                stderr,
+                exit_code: status.code(),
+            }
+            .into());
         }
 
-        if !fs::try_exists(&dest.parent().unwrap()).await? {
-            fs::create_dir_all(dest.parent().unwrap()).await?;
+        async {
+            if !fs::try_exists(&dest.parent().unwrap()).await? {
+                fs::create_dir_all(dest.parent().unwrap()).await?;
+            }
+
+            fs::rename(&rendered, dest).await?;
+            compress::precompress_tree(dest).await?;
+
+            Ok::<(), Box<dyn Error>>(())
         }
+        .instrument(tracing::info_span!("rename"))
+        .await?;
+
+        Ok(())
+    }
+
+    fn parse_progress(line: &str) -> Option<u8> {
+        let percent_idx = line.find('%')?;
+        let digits_start = line[..percent_idx]
+            .rfind(|c: char| !c.is_ascii_digit())
+            .map(|i| i + 1)
+            .unwrap_or(0);
 
-        Ok(fs::rename(rendered, dest).await?)
+        line[digits_start..percent_idx].parse().ok()
     }
 
     pub async fn clean() {
@@ -321,34 +453,58 @@ impl Map {
                         .await?
                         .into_response(req)
                 }
-                ["maps", _, ..]
-                    if fs::try_exists(
-                        map_path.join(req_path.iter().skip(2).collect::<PathBuf>()),
-                    )
-                    .await? =>
-                {
-                    NamedFile::open_async(
-                        map_path.join(req_path.iter().skip(2).collect::<PathBuf>()),
-                    )
-                    .await?
-                    .into_response(req)
-                }
-                ["maps", _, ..]
-                    if fs::try_exists(
-                        map_path.join(req_path.iter().skip(2).collect::<PathBuf>().with_file_name(
-                            format!("{}.gz", req_path.file_name().unwrap().to_string_lossy()),
-                        )),
-                    )
-                    .await? =>
-                {
-                    NamedFile::open_async(
-                        map_path.join(req_path.iter().skip(2).collect::<PathBuf>().with_file_name(
-                            format!("{}.gz", req_path.file_name().unwrap().to_string_lossy()),
-                        )),
-                    )
-                    .await?
-                    .set_content_encoding(actix_web::http::header::ContentEncoding::Gzip)
-                    .into_response(req)
+                ["maps", _, ..] => {
+                    let file_path = map_path.join(req_path.iter().skip(2).collect::<PathBuf>());
+
+                    if !fs::try_exists(&file_path).await? {
+                        HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+                    } else if !compress::is_compressible(&file_path) {
+                        NamedFile::open_async(&file_path).await?.into_response(req)
+                    } else {
+                        let encoding = compress::PreferredEncoding::from_headers(req.headers());
+
+                        match encoding.content_encoding() {
+                            None => NamedFile::open_async(&file_path).await?.into_response(req),
+                            Some(content_encoding) => {
+                                // The precompressed sibling and the in-memory
+                                // fallback both lose the real file extension,
+                                // so the MIME type has to be derived from
+                                // `file_path` rather than guessed by either.
+                                let content_type =
+                                    mime_guess::from_path(&file_path).first_or_octet_stream();
+
+                                let precompressed = compress::with_appended_extension(
+                                    &file_path,
+                                    encoding.extension().unwrap(),
+                                );
+
+                                if fs::try_exists(&precompressed).await? {
+                                    NamedFile::open_async(&precompressed)
+                                        .await?
+                                        .set_content_type(content_type)
+                                        .set_content_encoding(content_encoding)
+                                        .into_response(req)
+                                } else {
+                                    let compressed = compress::compress_on_the_fly(
+                                        &fs::read(&file_path).await?,
+                                        encoding,
+                                    )
+                                    .await?;
+
+                                    HttpResponseBuilder::new(StatusCode::OK)
+                                        .insert_header((
+                                            actix_web::http::header::CONTENT_TYPE,
+                                            content_type.as_ref(),
+                                        ))
+                                        .insert_header((
+                                            actix_web::http::header::CONTENT_ENCODING,
+                                            encoding.header_value().unwrap(),
+                                        ))
+                                        .body(compressed)
+                                }
+                            }
+                        }
+                    }
                 }
                 ["settings.json"] => {
                     let settings = SettingsGlobal {
@@ -0,0 +1,99 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::mpsc::UnboundedSender;
+use zip::ZipArchive;
+
+#[derive(Debug)]
+pub enum ExtractError {
+    Io(io::Error),
+    Zip(zip::result::ZipError),
+    PathTraversal(String),
+    Cancelled,
+}
+
+impl Display for ExtractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Zip(e) => write!(f, "{e}"),
+            Self::PathTraversal(name) => {
+                write!(f, "archive entry escapes destination: {name}")
+            }
+            Self::Cancelled => f.write_str("extraction cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+impl From<io::Error> for ExtractError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for ExtractError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::Zip(e)
+    }
+}
+
+pub async fn extract(
+    archive: PathBuf,
+    dest: PathBuf,
+    cancelled: Arc<AtomicBool>,
+    progress: UnboundedSender<u8>,
+) -> Result<(), ExtractError> {
+    tokio::task::spawn_blocking(move || extract_blocking(&archive, &dest, &cancelled, &progress))
+        .await
+        .expect("extract task panicked")
+}
+
+fn extract_blocking(
+    archive: &Path,
+    dest: &Path,
+    cancelled: &AtomicBool,
+    progress: &UnboundedSender<u8>,
+) -> Result<(), ExtractError> {
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i)?;
+        if entry.enclosed_name().is_none() {
+            return Err(ExtractError::PathTraversal(entry.name().to_string()));
+        }
+    }
+
+    std::fs::create_dir_all(dest)?;
+
+    for i in 0..zip.len() {
+        if cancelled.load(Ordering::SeqCst) {
+            return Err(ExtractError::Cancelled);
+        }
+
+        let _ = progress.send((i * 100 / zip.len()) as u8);
+
+        let mut entry = zip.by_index(i)?;
+        let out_path = dest.join(entry.enclosed_name().expect("validated above"));
+
+        if entry.is_dir() {
+            std::fs::create_dir_all(&out_path)?;
+            continue;
+        }
+
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        io::copy(&mut entry, &mut File::create(&out_path)?)?;
+    }
+
+    let _ = progress.send(100);
+
+    Ok(())
+}